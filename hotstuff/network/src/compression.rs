@@ -0,0 +1,127 @@
+// Copyright(C) Facebook, Inc. and its affiliates.
+use crate::error::NetworkError;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// A frame codec a connection can advertise support for. Peers intersect their lists and pick
+/// the first mutually-supported codec in the local preference order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionCodec {
+    /// No compression; frames are carried as-is.
+    None,
+    Lz4,
+    Zstd { level: i32 },
+}
+
+/// Sent once per connection, right after framing and before any `DvfMessage` traffic, so both
+/// ends can agree on a codec. Older peers that don't know about this handshake simply never
+/// send one, which [`Receiver`](crate::receiver::Receiver) treats as "no compression".
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompressionAdvertisement {
+    pub supported: Vec<CompressionCodec>,
+}
+
+impl CompressionAdvertisement {
+    pub fn new(supported: Vec<CompressionCodec>) -> Self {
+        Self { supported }
+    }
+}
+
+/// Prefixes every encoded [`CompressionAdvertisement`] frame. bincode isn't self-describing, so
+/// a genuine first `DvfMessage { validator_id: 0, .. }` can happen to deserialize successfully
+/// as a (bogus) empty `CompressionAdvertisement` — this magic tag, rather than deserialize
+/// success, is what actually tells the two apart.
+const ADVERTISEMENT_MAGIC: [u8; 4] = *b"DVC1";
+
+/// Encode an advertisement with its magic tag so the receiving end can unambiguously recognize
+/// the frame as a handshake message rather than a `DvfMessage`.
+pub fn encode_advertisement(advertisement: &CompressionAdvertisement) -> Result<Vec<u8>, NetworkError> {
+    let mut bytes = ADVERTISEMENT_MAGIC.to_vec();
+    bytes.extend(bincode::serialize(advertisement).map_err(NetworkError::FailedToSerializeAdvertisement)?);
+    Ok(bytes)
+}
+
+/// Recognize and decode an advertisement frame. Returns `None` for anything not carrying the
+/// magic tag, which callers treat as a `DvfMessage` from a peer that doesn't speak the handshake.
+pub fn decode_advertisement(frame: &[u8]) -> Option<CompressionAdvertisement> {
+    let payload = frame.strip_prefix(&ADVERTISEMENT_MAGIC)?;
+    bincode::deserialize(payload).ok()
+}
+
+/// Pick the first codec in `local`'s preference order that `remote` also advertised, defaulting
+/// to `None` when the two sides share nothing in common. Zstd is matched by kind rather than by
+/// `PartialEq`, so two peers configured with different levels still agree on zstd (at the lower
+/// of the two levels) instead of falling through to a weaker codec.
+pub fn negotiate(local: &[CompressionCodec], remote: &[CompressionCodec]) -> CompressionCodec {
+    for codec in local {
+        match codec {
+            CompressionCodec::Zstd { level } => {
+                let remote_level = remote.iter().find_map(|c| match c {
+                    CompressionCodec::Zstd { level } => Some(*level),
+                    _ => None,
+                });
+                if let Some(remote_level) = remote_level {
+                    return CompressionCodec::Zstd { level: (*level).min(remote_level) };
+                }
+            }
+            other => {
+                if remote.contains(other) {
+                    return *other;
+                }
+            }
+        }
+    }
+    CompressionCodec::None
+}
+
+/// Compress a plaintext frame with the negotiated codec, applied right before the frame hits
+/// the wire (after this, `Writer` seals it if the transport is encrypted).
+pub fn compress(codec: CompressionCodec, data: &[u8]) -> Result<Vec<u8>, NetworkError> {
+    match codec {
+        CompressionCodec::None => Ok(data.to_vec()),
+        CompressionCodec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        CompressionCodec::Zstd { level } => {
+            zstd::stream::encode_all(data, level).map_err(NetworkError::CompressionError)
+        }
+    }
+}
+
+/// Upper bound on how large a single frame is allowed to decompress to, regardless of codec.
+/// `LengthDelimitedCodec`'s default max frame size only bounds the *compressed* bytes a peer can
+/// send in one frame; without this, a small frame engineered to expand into gigabytes (a
+/// decompression bomb) would still be decoded in full before `decompress` ever returns.
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Reverse of [`compress`], applied right after a frame is read off the wire (and decrypted,
+/// if the transport is encrypted) and before it is handed to `dispatch`. Rejects input that
+/// would decompress past [`MAX_DECOMPRESSED_SIZE`] instead of allocating for it.
+pub fn decompress(codec: CompressionCodec, data: &[u8]) -> Result<Vec<u8>, NetworkError> {
+    match codec {
+        CompressionCodec::None => Ok(data.to_vec()),
+        CompressionCodec::Lz4 => {
+            // The prepended length is attacker-controlled; reject it before `lz4_flex` ever
+            // allocates a buffer to decompress into.
+            if let Some(prefix) = data.get(..4) {
+                let declared_size = u32::from_le_bytes(prefix.try_into().unwrap()) as usize;
+                if declared_size > MAX_DECOMPRESSED_SIZE {
+                    return Err(NetworkError::DecompressedSizeExceeded);
+                }
+            }
+            lz4_flex::decompress_size_prepended(data).map_err(NetworkError::DecompressionError)
+        }
+        CompressionCodec::Zstd { .. } => {
+            // zstd frames don't reliably bound their own output size up front, so cap the
+            // number of bytes actually read out of the decoder instead of trusting the frame.
+            let decoder = zstd::stream::read::Decoder::new(data).map_err(NetworkError::CompressionError)?;
+            let mut buf = Vec::new();
+            decoder
+                .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                .read_to_end(&mut buf)
+                .map_err(NetworkError::CompressionError)?;
+            if buf.len() > MAX_DECOMPRESSED_SIZE {
+                return Err(NetworkError::DecompressedSizeExceeded);
+            }
+            Ok(buf)
+        }
+    }
+}
@@ -1,5 +1,7 @@
 // Copyright(C) Facebook, Inc. and its affiliates.
+use crate::compression::{self, CompressionAdvertisement, CompressionCodec};
 use crate::error::NetworkError;
+use crate::transport::{self, TransportCipher, TransportConfig, TransportMode};
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::stream::SplitSink;
@@ -9,18 +11,94 @@ use std::error::Error;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc};
-use tokio::sync::{RwLock};
+use std::time::Duration;
+use tokio::sync::{watch, Notify, RwLock, Semaphore};
 use crate::dvf_message::DvfMessage;
 use futures::SinkExt;
 
+/// Default cap on the number of runners a `Receiver` keeps alive at once, used by
+/// [`ReceiverConfig::default`].
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1_000;
+
+/// Reserved `validator_id` used for heartbeat pings, so they never collide with a real handler
+/// and never trigger the "no handler found" path.
+const HEARTBEAT_VALIDATOR_ID: u64 = u64::MAX;
+
+/// How long a connection may stay silent before the `Receiver` sends a heartbeat ping.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many consecutive unanswered pings are tolerated before a connection is closed.
+pub const DEFAULT_MAX_MISSED_PINGS: u32 = 3;
+
+/// Codecs this node advertises during the compression handshake, in preference order.
+pub fn default_supported_codecs() -> Vec<CompressionCodec> {
+    vec![
+        CompressionCodec::Zstd { level: 3 },
+        CompressionCodec::Lz4,
+        CompressionCodec::None,
+    ]
+}
+
+/// What to do with an incoming connection once `max_connections` runners are already live.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionLimitMode {
+    /// Hold the accepted socket open and wait for a permit to free up.
+    Wait,
+    /// Immediately tell the peer the server is busy and drop the socket.
+    Reject,
+}
+
 #[cfg(test)]
 #[path = "tests/receiver_tests.rs"]
 pub mod receiver_tests;
 
-/// Convenient alias for the writer end of the TCP channel.
-pub type Writer = SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>;
+/// Writer end of a connection. Wraps the raw length-delimited sink and, when the transport
+/// negotiated a Noise session, transparently seals every frame before it hits the wire so
+/// `MessageHandler::dispatch` implementations never have to know whether encryption is on.
+pub struct Writer {
+    inner: SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>,
+    cipher: Option<Arc<TransportCipher>>,
+    /// Codec negotiated by the compression handshake; `CompressionCodec::None` until (and
+    /// unless) negotiation completes.
+    codec: CompressionCodec,
+}
+
+impl Writer {
+    fn new(
+        inner: SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>,
+        cipher: Option<Arc<TransportCipher>>,
+    ) -> Self {
+        Self { inner, cipher, codec: CompressionCodec::None }
+    }
+
+    fn set_codec(&mut self, codec: CompressionCodec) {
+        self.codec = codec;
+    }
+
+    /// Send a plaintext message: compress it with the negotiated codec, then seal it if the
+    /// connection is encrypted.
+    pub async fn send(&mut self, message: Bytes) -> Result<(), NetworkError> {
+        let compressed = compression::compress(self.codec, &message)?;
+        let frame = match &self.cipher {
+            Some(cipher) => cipher.seal(&compressed).await?,
+            None => Bytes::from(compressed),
+        };
+        self.inner
+            .send(frame)
+            .await
+            .map_err(NetworkError::FailedToSendMessage)
+    }
+
+    /// Flush any buffered output. Used to make sure nothing is left in flight when a runner is
+    /// asked to close, whether by shutdown or by eviction.
+    pub async fn flush(&mut self) -> Result<(), NetworkError> {
+        self.inner.flush().await.map_err(NetworkError::FailedToSendMessage)
+    }
+}
+
 #[async_trait]
 pub trait MessageHandler: Clone + Send + Sync + 'static {
     /// Defines how to handle an incoming message. A typical usage is to define a `MessageHandler` with a
@@ -30,87 +108,592 @@ pub trait MessageHandler: Clone + Send + Sync + 'static {
     async fn dispatch(&self, writer: &mut Writer, message: Bytes) -> Result<(), Box<dyn Error>>;
 }
 
+/// Settings controlling how a `Receiver` accepts and caps connections.
+pub struct ReceiverConfig {
+    /// Controls whether incoming connections must complete a Noise handshake before any
+    /// `DvfMessage` is processed, and which peers are allowed through it.
+    pub transport: TransportConfig,
+    /// Maximum number of runners kept alive concurrently.
+    pub max_connections: usize,
+    /// What happens to a new connection once `max_connections` is reached.
+    pub limit_mode: ConnectionLimitMode,
+    /// How long a connection may stay silent before a heartbeat ping is sent.
+    pub idle_timeout: Duration,
+    /// How many consecutive unanswered pings are tolerated before the connection is closed.
+    pub max_missed_pings: u32,
+    /// Codecs advertised during the compression handshake, in preference order.
+    pub supported_codecs: Vec<CompressionCodec>,
+}
+
+impl Default for ReceiverConfig {
+    fn default() -> Self {
+        Self {
+            transport: TransportConfig::plaintext(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            limit_mode: ConnectionLimitMode::Wait,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_missed_pings: DEFAULT_MAX_MISSED_PINGS,
+            supported_codecs: default_supported_codecs(),
+        }
+    }
+}
+
+/// Tracks, for every live connection, the `validator_id` its most recently routed message
+/// targeted, so a deregistered validator's connections can be closed proactively instead of
+/// waiting for them to eventually hit "no handler found" on their own.
+#[derive(Default)]
+struct ConnectionRegistry {
+    last_routed: RwLock<HashMap<SocketAddr, u64>>,
+    by_validator: RwLock<HashMap<u64, HashSet<SocketAddr>>>,
+    close_signals: RwLock<HashMap<SocketAddr, Arc<Notify>>>,
+}
+
+impl ConnectionRegistry {
+    /// Register a new connection and return the `Notify` it should select on to learn it's
+    /// been targeted for eviction.
+    async fn register(&self, peer: SocketAddr) -> Arc<Notify> {
+        let notify = Arc::new(Notify::new());
+        self.close_signals.write().await.insert(peer, notify.clone());
+        notify
+    }
+
+    async fn unregister(&self, peer: SocketAddr) {
+        self.close_signals.write().await.remove(&peer);
+        if let Some(validator_id) = self.last_routed.write().await.remove(&peer) {
+            if let Some(peers) = self.by_validator.write().await.get_mut(&validator_id) {
+                peers.remove(&peer);
+            }
+        }
+    }
+
+    async fn record_routed(&self, peer: SocketAddr, validator_id: u64) {
+        let previous = self.last_routed.write().await.insert(peer, validator_id);
+        let mut by_validator = self.by_validator.write().await;
+        if let Some(previous) = previous {
+            if previous != validator_id {
+                if let Some(peers) = by_validator.get_mut(&previous) {
+                    peers.remove(&peer);
+                }
+            }
+        }
+        by_validator.entry(validator_id).or_default().insert(peer);
+    }
+
+    /// Evict every connection whose last routed message targeted `validator_id`.
+    async fn close_connections_for(&self, validator_id: u64) {
+        let peers = self
+            .by_validator
+            .write()
+            .await
+            .remove(&validator_id)
+            .unwrap_or_default();
+        let close_signals = self.close_signals.read().await;
+        for peer in peers {
+            if let Some(notify) = close_signals.get(&peer) {
+                notify.notify_waiters();
+            }
+        }
+    }
+}
+
+/// Bundles the per-connection settings a runner needs, so `spawn_runner`/`handle_connection`
+/// don't have to grow a new parameter every time a request adds another knob.
+struct ConnectionParams<Handler: MessageHandler> {
+    handler_map: Arc<RwLock<HashMap<u64, Handler>>>,
+    name: &'static str,
+    transport_config: Arc<TransportConfig>,
+    idle_timeout: Duration,
+    max_missed_pings: u32,
+    supported_codecs: Arc<Vec<CompressionCodec>>,
+    registry: Arc<ConnectionRegistry>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl<Handler: MessageHandler> Clone for ConnectionParams<Handler> {
+    fn clone(&self) -> Self {
+        Self {
+            handler_map: self.handler_map.clone(),
+            name: self.name,
+            transport_config: self.transport_config.clone(),
+            idle_timeout: self.idle_timeout,
+            max_missed_pings: self.max_missed_pings,
+            supported_codecs: self.supported_codecs.clone(),
+            registry: self.registry.clone(),
+            shutdown_rx: self.shutdown_rx.clone(),
+        }
+    }
+}
+
+/// A handle to a running `Receiver`, returned by [`Receiver::spawn_with_config`]. Lets callers
+/// shut the receiver down, wait for its connections to drain, and evict a validator's handler.
+pub struct ReceiverHandle<Handler: MessageHandler> {
+    handler_map: Arc<RwLock<HashMap<u64, Handler>>>,
+    registry: Arc<ConnectionRegistry>,
+    shutdown_tx: watch::Sender<bool>,
+    active_connections: Arc<AtomicUsize>,
+    drain_notify: Arc<Notify>,
+}
+
+impl<Handler: MessageHandler> ReceiverHandle<Handler> {
+    /// Stop accepting new connections and ask every live runner to finish its current dispatch,
+    /// flush its `Writer`, and close.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(true);
+    }
+
+    /// Number of runners currently alive.
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Wait until every connection has drained. Typically called after [`Self::shutdown`].
+    pub async fn wait_for_drain(&self) {
+        loop {
+            let notified = self.drain_notify.notified();
+            if self.active_connections() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Remove `validator_id`'s handler and proactively close any connection whose last routed
+    /// message targeted it, instead of leaving it to hit "no handler found" on its own.
+    pub async fn deregister(&self, validator_id: u64) {
+        self.handler_map.write().await.remove(&validator_id);
+        self.registry.close_connections_for(validator_id).await;
+    }
+}
+
 /// For each incoming request, we spawn a new runner responsible to receive messages and forward them
 /// through the provided deliver channel.
 pub struct Receiver<Handler: MessageHandler> {
     /// Address to listen to.
     address: SocketAddr,
-    /// Struct responsible to define how to handle received messages.
-    handler_map: Arc<RwLock<HashMap<u64, Handler>>>,
-    name: &'static str,
+    params: ConnectionParams<Handler>,
+    limit_mode: ConnectionLimitMode,
+    /// Caps the number of runners alive at once; a permit is held for the life of each runner.
+    connection_semaphore: Arc<Semaphore>,
+    /// Live connection count, kept in lockstep with the semaphore's outstanding permits.
+    active_connections: Arc<AtomicUsize>,
+    drain_notify: Arc<Notify>,
 }
 
 impl<Handler: MessageHandler> Receiver<Handler> {
-    /// Spawn a new network receiver handling connections from any incoming peer.
+    /// Spawn a new network receiver handling connections from any incoming peer, using the
+    /// default config (plaintext transport, `DEFAULT_MAX_CONNECTIONS` cap, wait-for-permit).
+    /// Returns no handle: the receiver runs until the process exits. The `ReceiverHandle`
+    /// produced internally is leaked rather than dropped, since dropping its shutdown sender
+    /// must never be mistaken for a caller requesting shutdown.
     pub fn spawn(address: SocketAddr, handler_map: Arc<RwLock<HashMap<u64, Handler>>>, name: &'static str) {
-        tokio::spawn(async move {
-            Self { address, handler_map, name}.run().await;
+        std::mem::forget(Self::spawn_with_config(address, handler_map, name, ReceiverConfig::default()));
+    }
+
+    /// Spawn a new network receiver with an explicit [`ReceiverConfig`], returning a
+    /// [`ReceiverHandle`] that can shut it down, wait for a full drain, or deregister a validator.
+    pub fn spawn_with_config(
+        address: SocketAddr,
+        handler_map: Arc<RwLock<HashMap<u64, Handler>>>,
+        name: &'static str,
+        config: ReceiverConfig,
+    ) -> ReceiverHandle<Handler> {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let registry = Arc::new(ConnectionRegistry::default());
+        let params = ConnectionParams {
+            handler_map: handler_map.clone(),
+            name,
+            transport_config: Arc::new(config.transport),
+            idle_timeout: config.idle_timeout,
+            max_missed_pings: config.max_missed_pings,
+            supported_codecs: Arc::new(config.supported_codecs),
+            registry: registry.clone(),
+            shutdown_rx,
+        };
+        let connection_semaphore = Arc::new(Semaphore::new(config.max_connections));
+        let active_connections = Arc::new(AtomicUsize::new(0));
+        let drain_notify = Arc::new(Notify::new());
+        tokio::spawn({
+            let active_connections = active_connections.clone();
+            let drain_notify = drain_notify.clone();
+            async move {
+                Self {
+                    address,
+                    params,
+                    limit_mode: config.limit_mode,
+                    connection_semaphore,
+                    active_connections,
+                    drain_notify,
+                }
+                .run()
+                .await;
+            }
         });
+        ReceiverHandle { handler_map, registry, shutdown_tx, active_connections, drain_notify }
     }
 
     /// Main loop responsible to accept incoming connections and spawn a new runner to handle it.
+    /// Stops accepting (without dropping already-live runners) once a shutdown is signaled.
     async fn run(&self) {
         let listener = TcpListener::bind(&self.address)
             .await
             .expect(format!("Failed to bind TCP address {}", self.address).as_str());
 
-        info!("Listening on {}. [{:?}]", self.address, self.name);
+        info!("Listening on {}. [{:?}]", self.address, self.params.name);
+        let mut shutdown_rx = self.params.shutdown_rx.clone();
+        // Once the shutdown sender is dropped (rather than sent `true`), `changed()` resolves
+        // immediately forever; the `if !shutdown_closed` guard stops that from becoming a
+        // busy-loop and, crucially, keeps a dropped handle from ever being treated as an
+        // explicit shutdown request.
+        let mut shutdown_closed = false;
         loop {
-            let (socket, peer) = match listener.accept().await {
-                Ok(value) => value,
-                Err(e) => {
-                    warn!("{}", NetworkError::FailedToListen(e));
-                    continue;
+            tokio::select! {
+                biased;
+                changed = shutdown_rx.changed(), if !shutdown_closed => {
+                    match changed {
+                        Ok(()) if *shutdown_rx.borrow() => {
+                            info!("Shutting down, no longer accepting new connections. [{:?}]", self.params.name);
+                            break;
+                        }
+                        Ok(()) => {}
+                        Err(_) => shutdown_closed = true,
+                    }
                 }
-            };
-            debug!("Incoming connection established with {}. Local: {}. [{:?}]", peer, self.address, self.name);
-            self.spawn_runner(socket, peer).await;
+                accepted = listener.accept() => {
+                    let (socket, peer) = match accepted {
+                        Ok(value) => value,
+                        Err(e) => {
+                            warn!("{}", NetworkError::FailedToListen(e));
+                            continue;
+                        }
+                    };
+                    debug!("Incoming connection established with {}. Local: {}. [{:?}]", peer, self.address, self.params.name);
+                    self.spawn_runner(socket, peer);
+                }
+            }
         }
     }
 
-    async fn spawn_runner(&self, socket: TcpStream, peer: SocketAddr) {
-        let handler_map = self.handler_map.clone(); 
-        let name = self.name.clone();
+    /// Kick off handling of a freshly-accepted socket. Permit acquisition (which, in `Wait`
+    /// mode, can block indefinitely while the semaphore is saturated) happens inside the
+    /// spawned task rather than here, so it never stalls `run`'s accept loop and its shutdown
+    /// check.
+    fn spawn_runner(&self, socket: TcpStream, peer: SocketAddr) {
+        let params = self.params.clone();
+        let name = params.name;
+        let active_connections = self.active_connections.clone();
+        let drain_notify = self.drain_notify.clone();
+        let registry = params.registry.clone();
+        let semaphore = self.connection_semaphore.clone();
+        let limit_mode = self.limit_mode;
 
         tokio::spawn(async move {
-            let transport = Framed::new(socket, LengthDelimitedCodec::new());
-            let (mut writer, mut reader) = transport.split();
-            while let Some(frame) = reader.next().await {
-                match frame.map_err(|e| NetworkError::FailedToReceiveMessage(peer, e)) {
-                    Ok(message) => {
-                        // get validator
-                        match bincode::deserialize::<DvfMessage>(&message[..]) {
-                            Ok(dvf_message) => {
-                                let validator_id = dvf_message.validator_id;
-                                match handler_map.read().await.get(&validator_id) {
-                                    Some(handler) => {
-                                        // trunctate the prefix
-                                        let msg = dvf_message.message;
-                                        if let Err(e) = handler.dispatch(&mut writer, Bytes::from(msg)).await {
-                                            error!("[VA {}] Handler dispatch error ({})", validator_id, e);
-                                            return;
-                                        }
-                                    },
-                                    None => {
-                                        // [zico] Constantly review this. For now, we sent back a message, which is different from a normal 'Ack' message
-                                        let _ = writer.send(Bytes::from("No handler found")).await;
-                                        error!("[VA {}] Receive a message, but no handler found! [{:?}]", validator_id, name);                                    
+            let permit = match limit_mode {
+                ConnectionLimitMode::Wait => match semaphore.acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                },
+                ConnectionLimitMode::Reject => match semaphore.try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        warn!("Connection limit reached, rejecting {}. [{:?}]", peer, name);
+                        Self::reject_connection(socket, &params.transport_config).await;
+                        return;
+                    }
+                },
+            };
+
+            active_connections.fetch_add(1, Ordering::SeqCst);
+            let close_notify = registry.register(peer).await;
+            let _permit = permit;
+            Self::handle_connection(socket, peer, params, close_notify).await;
+            registry.unregister(peer).await;
+            active_connections.fetch_sub(1, Ordering::SeqCst);
+            drain_notify.notify_waiters();
+        });
+    }
+
+    /// Tell a peer the server is at capacity and close the socket, used in `Reject` mode. Only
+    /// plaintext transports get the "server busy" frame: a bare length-delimited frame written
+    /// straight onto a socket that's supposed to be running the Noise handshake is just noise
+    /// the peer can't parse, so an `Encrypted` transport closes the socket without replying.
+    async fn reject_connection(socket: TcpStream, transport_config: &TransportConfig) {
+        if transport_config.mode == TransportMode::Plaintext {
+            let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
+            let _ = transport.send(Bytes::from("server busy")).await;
+        }
+    }
+
+    async fn handle_connection(
+        mut socket: TcpStream,
+        peer: SocketAddr,
+        params: ConnectionParams<Handler>,
+        close_notify: Arc<Notify>,
+    ) {
+        let ConnectionParams {
+            handler_map,
+            name,
+            transport_config,
+            idle_timeout,
+            max_missed_pings,
+            supported_codecs,
+            registry,
+            mut shutdown_rx,
+        } = params;
+
+        // Once the shutdown sender is dropped (rather than sent `true`), `changed()` resolves
+        // immediately forever; the `if !shutdown_closed` guard stops that from becoming a
+        // busy-loop and, crucially, keeps a dropped handle from ever being treated as an
+        // explicit shutdown request. Shared across the handshake wait below, the compression
+        // wait, and the main loop further down, since all three select on the same receiver.
+        let mut shutdown_closed = false;
+
+        // A peer that opens the socket and never sends handshake bytes must not park this
+        // runner (and the permit it's holding) forever, so the handshake itself is bounded by
+        // `idle_timeout` and responsive to shutdown/eviction just like the rest of the
+        // connection's lifetime below.
+        let handshake = loop {
+            tokio::select! {
+                biased;
+                changed = shutdown_rx.changed(), if !shutdown_closed => {
+                    match changed {
+                        Ok(()) if *shutdown_rx.borrow() => {
+                            debug!("Closing connection with {} before handshake completed (shutdown). [{:?}]", peer, name);
+                            return;
+                        }
+                        Ok(()) => {}
+                        Err(_) => shutdown_closed = true,
+                    }
+                }
+                _ = close_notify.notified() => {
+                    debug!("Evicting connection with {} before handshake completed. [{:?}]", peer, name);
+                    return;
+                }
+                result = tokio::time::timeout(idle_timeout, transport::upgrade_responder(&mut socket, &transport_config)) => break result,
+            }
+        };
+        let cipher = match handshake {
+            Ok(Ok(cipher)) => cipher.map(Arc::new),
+            Ok(Err(e)) => {
+                warn!("Noise handshake with {} failed ({}). [{:?}]", peer, e, name);
+                return;
+            }
+            Err(_elapsed) => {
+                warn!("Noise handshake with {} timed out. [{:?}]", peer, name);
+                return;
+            }
+        };
+
+        let transport = Framed::new(socket, LengthDelimitedCodec::new());
+        let (sink, mut reader) = transport.split();
+        let mut writer = Writer::new(sink, cipher.clone());
+        // Compression capability handshake: advertise what we support, then peek at the first
+        // frame. A peer that speaks the handshake answers with its own advertisement, which we
+        // negotiate and consume. An older peer that doesn't know about it just starts sending
+        // `DvfMessage` frames straight away, so we fall back to `None` and feed that first frame
+        // into the normal processing path below instead of dropping it.
+        let advertisement = CompressionAdvertisement::new(supported_codecs.as_ref().clone());
+        let advertisement_bytes = match compression::encode_advertisement(&advertisement) {
+            Ok(bytes) => Bytes::from(bytes),
+            Err(e) => {
+                warn!("Failed to serialize compression advertisement ({})", e);
+                return;
+            }
+        };
+        if let Err(e) = writer.send(advertisement_bytes).await {
+            warn!("Failed to send compression advertisement to {} ({})", peer, e);
+            return;
+        }
+
+        let mut codec = CompressionCodec::None;
+        let mut pending_message = None;
+        let first_frame = loop {
+            tokio::select! {
+                biased;
+                changed = shutdown_rx.changed(), if !shutdown_closed => {
+                    match changed {
+                        Ok(()) if *shutdown_rx.borrow() => {
+                            debug!("Closing connection with {} for shutdown. [{:?}]", peer, name);
+                            let _ = writer.flush().await;
+                            return;
+                        }
+                        Ok(()) => {}
+                        Err(_) => shutdown_closed = true,
+                    }
+                }
+                _ = close_notify.notified() => {
+                    debug!("Evicting connection with {}. [{:?}]", peer, name);
+                    let _ = writer.flush().await;
+                    return;
+                }
+                frame = tokio::time::timeout(idle_timeout, reader.next()) => break frame,
+            }
+        };
+        match first_frame {
+            Ok(Some(Ok(frame))) => {
+                let plaintext = match &cipher {
+                    Some(cipher) => match cipher.open(&frame).await {
+                        Ok(plaintext) => plaintext,
+                        Err(e) => {
+                            warn!("Failed to decrypt frame from {} ({})", peer, e);
+                            return;
+                        }
+                    },
+                    None => Bytes::from(frame.to_vec()),
+                };
+                match compression::decode_advertisement(&plaintext) {
+                    Some(remote) => {
+                        codec = compression::negotiate(supported_codecs.as_ref(), &remote.supported);
+                        writer.set_codec(codec);
+                        debug!("Negotiated {:?} compression with {}", codec, peer);
+                    }
+                    None => pending_message = Some(plaintext),
+                }
+            }
+            Ok(Some(Err(e))) => {
+                warn!("{}", NetworkError::FailedToReceiveMessage(peer, e));
+                return;
+            }
+            Ok(None) => {
+                warn!("Connection closed by peer {}", peer);
+                return;
+            }
+            // Peer hasn't sent anything yet; keep the default `None` codec and let the main
+            // loop's own idle-timeout/heartbeat logic take over from here.
+            Err(_elapsed) => {}
+        }
+
+        if let Some(message) = pending_message {
+            if !Self::process_dvf_message(&mut writer, &handler_map, &registry, peer, name, message).await {
+                return;
+            }
+        }
+
+        let mut missed_pings = 0u32;
+        loop {
+            tokio::select! {
+                biased;
+                changed = shutdown_rx.changed(), if !shutdown_closed => {
+                    match changed {
+                        Ok(()) if *shutdown_rx.borrow() => {
+                            debug!("Closing connection with {} for shutdown. [{:?}]", peer, name);
+                            break;
+                        }
+                        Ok(()) => {}
+                        Err(_) => shutdown_closed = true,
+                    }
+                }
+                _ = close_notify.notified() => {
+                    debug!("Evicting connection with {}. [{:?}]", peer, name);
+                    break;
+                }
+                frame = tokio::time::timeout(idle_timeout, reader.next()) => {
+                    let frame = match frame {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(_elapsed) => {
+                            missed_pings += 1;
+                            if missed_pings > max_missed_pings {
+                                warn!(
+                                    "Peer {} unresponsive after {} missed pings, closing connection. [{:?}]",
+                                    peer, max_missed_pings, name
+                                );
+                                return;
+                            }
+                            if let Err(e) = Self::send_heartbeat(&mut writer).await {
+                                warn!("Failed to send heartbeat ping to {} ({})", peer, e);
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+                    missed_pings = 0;
+
+                    match frame.map_err(|e| NetworkError::FailedToReceiveMessage(peer, e)) {
+                        Ok(message) => {
+                            let message = match &cipher {
+                                Some(cipher) => match cipher.open(&message).await {
+                                    Ok(plaintext) => plaintext,
+                                    Err(e) => {
+                                        warn!("Failed to decrypt frame from {} ({})", peer, e);
+                                        return;
                                     }
+                                },
+                                None => Bytes::from(message.to_vec()),
+                            };
+                            let message = match compression::decompress(codec, &message) {
+                                Ok(plaintext) => Bytes::from(plaintext),
+                                Err(e) => {
+                                    warn!("Failed to decompress frame from {} ({})", peer, e);
+                                    return;
                                 }
-                            },
-                            Err(e) => {
-                                warn!("can't deserialize {}", e);
+                            };
+                            if !Self::process_dvf_message(&mut writer, &handler_map, &registry, peer, name, message).await {
                                 return;
                             }
                         }
+                        Err(e) => {
+                            warn!("{}", e);
+                            return;
+                        }
                     }
-                    Err(e) => {
-                        warn!("{}", e);
-                        return;
+                }
+            }
+        }
+        let _ = writer.flush().await;
+        warn!("Connection closed with {}. [{:?}]", peer, name);
+    }
+
+    /// Deserialize and route a single plaintext `DvfMessage`. Returns `false` when the
+    /// connection should be closed (bad frame or a handler that errored out).
+    async fn process_dvf_message(
+        writer: &mut Writer,
+        handler_map: &RwLock<HashMap<u64, Handler>>,
+        registry: &ConnectionRegistry,
+        peer: SocketAddr,
+        name: &'static str,
+        message: Bytes,
+    ) -> bool {
+        match bincode::deserialize::<DvfMessage>(&message[..]) {
+            Ok(dvf_message) => {
+                let validator_id = dvf_message.validator_id;
+                if validator_id == HEARTBEAT_VALIDATOR_ID {
+                    // Peer answering (or itself sending) a heartbeat ping; the caller's
+                    // missed-pings counter already treats this as liveness.
+                    return true;
+                }
+                match handler_map.read().await.get(&validator_id) {
+                    Some(handler) => {
+                        registry.record_routed(peer, validator_id).await;
+                        // trunctate the prefix
+                        let msg = dvf_message.message;
+                        if let Err(e) = handler.dispatch(writer, Bytes::from(msg)).await {
+                            error!("[VA {}] Handler dispatch error ({})", validator_id, e);
+                            return false;
+                        }
+                        true
+                    },
+                    None => {
+                        // [zico] Constantly review this. For now, we sent back a message, which is different from a normal 'Ack' message
+                        let _ = writer.send(Bytes::from("No handler found")).await;
+                        error!("[VA {}] Receive a message, but no handler found! [{:?}]", validator_id, name);
+                        true
                     }
                 }
+            },
+            Err(e) => {
+                warn!("can't deserialize {}", e);
+                false
             }
-            warn!("Connection closed by peer {}", peer);
-        });
+        }
+    }
+
+    /// Send a reserved ping `DvfMessage` through `writer` to probe a silent connection.
+    async fn send_heartbeat(writer: &mut Writer) -> Result<(), NetworkError> {
+        let ping = DvfMessage {
+            validator_id: HEARTBEAT_VALIDATOR_ID,
+            message: Vec::new(),
+        };
+        let bytes = bincode::serialize(&ping).map_err(NetworkError::FailedToSerializeHeartbeat)?;
+        writer.send(Bytes::from(bytes)).await
     }
 }
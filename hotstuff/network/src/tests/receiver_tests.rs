@@ -0,0 +1,327 @@
+// Copyright(C) Facebook, Inc. and its affiliates.
+use super::*;
+use crate::dvf_message::DvfMessage;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+#[derive(Clone)]
+struct NoopHandler;
+
+#[async_trait::async_trait]
+impl MessageHandler for NoopHandler {
+    async fn dispatch(&self, _writer: &mut Writer, _message: Bytes) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+fn spawn_test_receiver(
+    address: SocketAddr,
+    max_connections: usize,
+    limit_mode: ConnectionLimitMode,
+) -> ReceiverHandle<NoopHandler> {
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map
+        .try_write()
+        .unwrap()
+        .insert(1u64, NoopHandler);
+    // Return the handle rather than dropping it here: dropping it would drop its shutdown
+    // sender, which `Receiver` does not treat as a shutdown request, but callers keep it alive
+    // for the whole test instead of relying on that.
+    Receiver::spawn_with_config(
+        address,
+        handler_map,
+        "receiver_tests",
+        ReceiverConfig {
+            transport: crate::transport::TransportConfig::plaintext(),
+            max_connections,
+            limit_mode,
+            ..ReceiverConfig::default()
+        },
+    )
+}
+
+async fn connect_and_send(address: SocketAddr) -> Framed<TcpStream, LengthDelimitedCodec> {
+    let stream = TcpStream::connect(address).await.unwrap();
+    let mut transport = Framed::new(stream, LengthDelimitedCodec::new());
+    let message = DvfMessage { validator_id: 1, message: vec![1, 2, 3] };
+    let bytes = bincode::serialize(&message).unwrap();
+    transport.send(Bytes::from(bytes)).await.unwrap();
+    transport
+}
+
+#[tokio::test]
+async fn rejects_connections_past_the_limit() {
+    let address: SocketAddr = "127.0.0.1:18901".parse().unwrap();
+    let _handle = spawn_test_receiver(address, 2, ConnectionLimitMode::Reject);
+    sleep(Duration::from_millis(100)).await;
+
+    let _first = connect_and_send(address).await;
+    let _second = connect_and_send(address).await;
+    sleep(Duration::from_millis(100)).await;
+
+    let mut third = connect_and_send(address).await;
+    let reply = third.next().await.unwrap().unwrap();
+    assert_eq!(&reply[..], b"server busy");
+}
+
+#[tokio::test]
+async fn reclaims_permits_on_disconnect() {
+    let address: SocketAddr = "127.0.0.1:18902".parse().unwrap();
+    let _handle = spawn_test_receiver(address, 1, ConnectionLimitMode::Reject);
+    sleep(Duration::from_millis(100)).await;
+
+    let first = connect_and_send(address).await;
+    sleep(Duration::from_millis(100)).await;
+
+    // Second connection is rejected while the first is still open.
+    let mut second = connect_and_send(address).await;
+    let reply = second.next().await.unwrap().unwrap();
+    assert_eq!(&reply[..], b"server busy");
+
+    // Closing the first connection frees its permit up for a new one.
+    drop(first);
+    sleep(Duration::from_millis(100)).await;
+    let mut third = connect_and_send(address).await;
+    // Accepted this time: the first frame is the compression advertisement, not "server busy".
+    let reply = third.next().await.unwrap().unwrap();
+    assert!(crate::compression::decode_advertisement(&reply[..]).is_some());
+    sleep(Duration::from_millis(100)).await;
+    drop(third);
+}
+
+#[tokio::test]
+async fn pings_and_closes_silent_connections() {
+    let address: SocketAddr = "127.0.0.1:18903".parse().unwrap();
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map.try_write().unwrap().insert(1u64, NoopHandler);
+    let _handle = Receiver::spawn_with_config(
+        address,
+        handler_map,
+        "receiver_tests",
+        ReceiverConfig {
+            transport: crate::transport::TransportConfig::plaintext(),
+            idle_timeout: Duration::from_millis(50),
+            max_missed_pings: 1,
+            ..ReceiverConfig::default()
+        },
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = TcpStream::connect(address).await.unwrap();
+    let mut transport = Framed::new(stream, LengthDelimitedCodec::new());
+
+    // First frame is always the compression capability advertisement.
+    let advertisement = transport.next().await.unwrap().unwrap();
+    assert!(crate::compression::decode_advertisement(&advertisement[..]).is_some());
+
+    // Stay silent afterwards: the receiver should ping us at least once...
+    let ping = transport.next().await.unwrap().unwrap();
+    let ping: DvfMessage = bincode::deserialize(&ping[..]).unwrap();
+    assert_eq!(ping.validator_id, HEARTBEAT_VALIDATOR_ID);
+
+    // ...and close the connection once `max_missed_pings` is exceeded.
+    assert!(transport.next().await.is_none());
+}
+
+#[derive(Clone)]
+struct EchoHandler {
+    sender: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+}
+
+#[async_trait::async_trait]
+impl MessageHandler for EchoHandler {
+    async fn dispatch(&self, _writer: &mut Writer, message: Bytes) -> Result<(), Box<dyn std::error::Error>> {
+        let _ = self.sender.send(message.to_vec());
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn negotiates_and_applies_compression() {
+    use crate::compression::{CompressionAdvertisement, CompressionCodec};
+
+    let address: SocketAddr = "127.0.0.1:18904".parse().unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map.try_write().unwrap().insert(1u64, EchoHandler { sender });
+    let _handle = Receiver::spawn_with_config(address, handler_map, "receiver_tests", ReceiverConfig::default());
+    sleep(Duration::from_millis(100)).await;
+
+    let stream = TcpStream::connect(address).await.unwrap();
+    let mut transport = Framed::new(stream, LengthDelimitedCodec::new());
+
+    // The server offers Zstd first, then Lz4; reply with an advertisement that only has Lz4
+    // so the two sides must settle on that.
+    let server_ad = transport.next().await.unwrap().unwrap();
+    let server_ad = crate::compression::decode_advertisement(&server_ad[..]).unwrap();
+    assert!(server_ad.supported.contains(&CompressionCodec::Lz4));
+
+    let our_ad = CompressionAdvertisement::new(vec![CompressionCodec::Lz4]);
+    let our_ad_bytes = crate::compression::encode_advertisement(&our_ad).unwrap();
+    transport.send(Bytes::from(our_ad_bytes)).await.unwrap();
+
+    let payload = b"hello compressed world".to_vec();
+    let dvf_message = DvfMessage { validator_id: 1, message: payload.clone() };
+    let plaintext = bincode::serialize(&dvf_message).unwrap();
+    let compressed = lz4_flex::compress_prepend_size(&plaintext);
+    transport.send(Bytes::from(compressed)).await.unwrap();
+
+    let dispatched = receiver.recv().await.unwrap();
+    assert_eq!(dispatched, payload);
+}
+
+#[tokio::test]
+async fn shutdown_drains_connections_gracefully() {
+    let address: SocketAddr = "127.0.0.1:18905".parse().unwrap();
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map.try_write().unwrap().insert(1u64, NoopHandler);
+    let handle = Receiver::spawn_with_config(
+        address,
+        handler_map,
+        "receiver_tests",
+        ReceiverConfig { transport: crate::transport::TransportConfig::plaintext(), ..ReceiverConfig::default() },
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    let mut first = connect_and_send(address).await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(handle.active_connections(), 1);
+    // Drain the compression advertisement the receiver always sends first.
+    first.next().await.unwrap().unwrap();
+
+    handle.shutdown();
+    handle.wait_for_drain().await;
+    assert_eq!(handle.active_connections(), 0);
+    assert!(first.next().await.is_none());
+
+    // New connections are no longer accepted once shut down.
+    assert!(TcpStream::connect(address).await.is_err());
+}
+
+#[tokio::test]
+async fn deregister_closes_connections_for_that_validator() {
+    let address: SocketAddr = "127.0.0.1:18906".parse().unwrap();
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map.try_write().unwrap().insert(1u64, NoopHandler);
+    let handle = Receiver::spawn_with_config(
+        address,
+        handler_map,
+        "receiver_tests",
+        ReceiverConfig { transport: crate::transport::TransportConfig::plaintext(), ..ReceiverConfig::default() },
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    let mut connection = connect_and_send(address).await;
+    sleep(Duration::from_millis(100)).await;
+    // Drain the compression advertisement the receiver always sends first.
+    connection.next().await.unwrap().unwrap();
+
+    handle.deregister(1).await;
+    assert!(connection.next().await.is_none());
+}
+
+#[tokio::test]
+async fn noise_handshake_authenticates_allowlisted_peer_and_seals_traffic() {
+    use crate::transport::{StaticKeypair, TransportConfig};
+
+    let address: SocketAddr = "127.0.0.1:18907".parse().unwrap();
+    let server_keypair = StaticKeypair::generate();
+    let client_keypair = StaticKeypair::generate();
+    let mut allowed = HashSet::new();
+    allowed.insert(client_keypair.public);
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map.try_write().unwrap().insert(1u64, EchoHandler { sender });
+    let _handle = Receiver::spawn_with_config(
+        address,
+        handler_map,
+        "receiver_tests",
+        ReceiverConfig { transport: TransportConfig::encrypted(server_keypair, allowed), ..ReceiverConfig::default() },
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let cipher = crate::transport::upgrade_initiator(&mut stream, &client_keypair).await.unwrap();
+    let mut transport = Framed::new(stream, LengthDelimitedCodec::new());
+
+    // First frame is the sealed compression advertisement.
+    let advertisement = transport.next().await.unwrap().unwrap();
+    let advertisement = cipher.open(&advertisement).await.unwrap();
+    assert!(crate::compression::decode_advertisement(&advertisement).is_some());
+
+    let payload = b"hello over noise".to_vec();
+    let dvf_message = DvfMessage { validator_id: 1, message: payload.clone() };
+    let plaintext = bincode::serialize(&dvf_message).unwrap();
+    let sealed = cipher.seal(&plaintext).await.unwrap();
+    transport.send(sealed).await.unwrap();
+
+    let dispatched = receiver.recv().await.unwrap();
+    assert_eq!(dispatched, payload);
+}
+
+#[tokio::test]
+async fn noise_handshake_rejects_disallowed_peer() {
+    use crate::transport::{StaticKeypair, TransportConfig};
+
+    let address: SocketAddr = "127.0.0.1:18908".parse().unwrap();
+    let server_keypair = StaticKeypair::generate();
+    let client_keypair = StaticKeypair::generate();
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map.try_write().unwrap().insert(1u64, NoopHandler);
+    // Allowlist deliberately omits the client's public key.
+    let _handle = Receiver::spawn_with_config(
+        address,
+        handler_map,
+        "receiver_tests",
+        ReceiverConfig { transport: TransportConfig::encrypted(server_keypair, HashSet::new()), ..ReceiverConfig::default() },
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    // The Noise handshake itself completes fine cryptographically from the initiator's side;
+    // it's the allowlist check afterwards that the server rejects on.
+    let _cipher = crate::transport::upgrade_initiator(&mut stream, &client_keypair).await.unwrap();
+
+    // The server closed the socket instead of proceeding to the compression handshake.
+    let mut transport = Framed::new(stream, LengthDelimitedCodec::new());
+    assert!(transport.next().await.is_none());
+}
+
+#[tokio::test]
+async fn stuck_handshake_is_reaped_and_does_not_block_drain() {
+    use crate::transport::{StaticKeypair, TransportConfig};
+
+    let address: SocketAddr = "127.0.0.1:18909".parse().unwrap();
+    let server_keypair = StaticKeypair::generate();
+    let handler_map = Arc::new(RwLock::new(HashMap::new()));
+    handler_map.try_write().unwrap().insert(1u64, NoopHandler);
+    let handle = Receiver::spawn_with_config(
+        address,
+        handler_map,
+        "receiver_tests",
+        ReceiverConfig {
+            transport: TransportConfig::encrypted(server_keypair, HashSet::new()),
+            idle_timeout: Duration::from_millis(500),
+            ..ReceiverConfig::default()
+        },
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    // Connect but never send a single handshake byte.
+    let _stream = TcpStream::connect(address).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(handle.active_connections(), 1);
+
+    // The stalled handshake is bounded by `idle_timeout`, so the runner (and the permit it's
+    // holding) is reclaimed instead of leaving `wait_for_drain` to hang forever.
+    tokio::time::timeout(Duration::from_secs(1), handle.wait_for_drain())
+        .await
+        .expect("drain should complete once the stuck handshake times out");
+    assert_eq!(handle.active_connections(), 0);
+}